@@ -1,6 +1,104 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+
 use crossfont::{GlyphKey, Rasterize};
 use tiny_skia::{Color, Pixmap, PixmapPaint, PixmapRef, Transform};
 
+/// Whether an RGBA glyph buffer holds real color data rather than a
+/// grayscale coverage mask duplicated across the R/G/B channels.
+fn is_color_bitmap(buffer: &[u8]) -> bool {
+    buffer.chunks(4).any(|px| px[0] != px[1] || px[1] != px[2])
+}
+
+/// Convert one pixel of a `BitmapBuffer::Rgba` color glyph into
+/// tiny-skia's premultiplied RGBA byte order. crossfont already hands
+/// back `Rgba` in R,G,B,A order premultiplied by alpha, so this is a
+/// straight pass-through; it exists as its own function so the byte
+/// order can be pinned down with a unit test instead of just a comment.
+fn color_glyph_pixel(px: &[u8]) -> [u8; 4] {
+    [px[0], px[1], px[2], px[3]]
+}
+
+/// A rasterized glyph kept around so repeated renders of the same title
+/// don't need to ask the rasterizer again.
+struct CachedGlyph {
+    glyph: crossfont::RasterizedGlyph,
+    /// Whether `glyph` is an already-colored bitmap (e.g. emoji) rather
+    /// than a coverage mask that gets tinted with `self.color`.
+    colored: bool,
+}
+
+/// Font families tried, in order, when a character isn't covered by the
+/// primary `sans-serif` face. These give us coverage for CJK text, emoji,
+/// and other symbols the primary face typically lacks.
+const FALLBACK_FAMILIES: &[&str] =
+    &["Noto Color Emoji", "Noto Sans CJK SC", "Noto Sans Symbols"];
+
+/// A fallback face, loaded lazily the first time it's actually needed.
+///
+/// `key` is tri-state: `None` means loading hasn't been tried yet,
+/// `Some(None)` means it was tried and failed (e.g. the family isn't
+/// installed), and `Some(Some(_))` means it loaded successfully. This
+/// way a missing fallback font is only ever probed once instead of on
+/// every call that hits an unresolved character.
+struct FallbackFont {
+    desc: crossfont::FontDesc,
+    key: Option<Option<crossfont::FontKey>>,
+}
+
+/// Default gamma used for the coverage-to-alpha lookup table. Targets
+/// typical sRGB displays; somewhere around 1.8-2.2 is the usual sweet
+/// spot for glyph antialiasing.
+const DEFAULT_GAMMA: f32 = 1.8;
+
+/// Default contrast boost applied on top of the gamma curve, keeping
+/// mid-tone coverage from looking washed out.
+const DEFAULT_CONTRAST: f32 = 0.1;
+
+/// Approximate titlebar background luminance used to pick which way the
+/// gamma curve should bend; Adwaita titlebars sit in a narrow light/dark
+/// band so this constant is close enough without per-theme plumbing.
+const APPROX_BACKGROUND_LUMINANCE: f32 = 0.5;
+
+/// Relative luminance of an sRGB color, per ITU-R BT.709 coefficients.
+fn luminance(color: Color) -> f32 {
+    0.2126 * color.red() + 0.7152 * color.green() + 0.0722 * color.blue()
+}
+
+/// Errors that can occur while rasterizing and compositing a title.
+///
+/// Exposing this lets callers decide how to react to a broken font
+/// subsystem (e.g. falling back to a blank title) instead of the failure
+/// being silently dropped.
+#[derive(Debug)]
+pub enum TitleTextError {
+    /// No loaded face, including all fallbacks, could produce a glyph for
+    /// this character.
+    MissingGlyph(char),
+    /// A font failed to load or rasterize.
+    FontNotLoaded(crossfont::Error),
+    /// Allocating the composited title pixmap failed.
+    PixmapAllocation,
+}
+
+impl std::fmt::Display for TitleTextError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MissingGlyph(c) => write!(f, "no font could render glyph for {c:?}"),
+            Self::FontNotLoaded(err) => write!(f, "font could not be loaded: {err}"),
+            Self::PixmapAllocation => write!(f, "failed to allocate title pixmap"),
+        }
+    }
+}
+
+impl std::error::Error for TitleTextError {}
+
+impl From<crossfont::Error> for TitleTextError {
+    fn from(err: crossfont::Error) -> Self {
+        Self::FontNotLoaded(err)
+    }
+}
+
 pub struct TitleText {
     title: String,
 
@@ -12,6 +110,14 @@ pub struct TitleText {
     rasterizer: crossfont::Rasterizer,
     color: Color,
 
+    glyph_cache: HashMap<GlyphKey, CachedGlyph>,
+    fallback_fonts: Vec<FallbackFont>,
+    char_font_cache: HashMap<char, crossfont::FontKey>,
+
+    gamma: f32,
+    contrast: f32,
+    gamma_lut: [u8; 256],
+
     pixmap: Option<Pixmap>,
 }
 
@@ -29,7 +135,7 @@ impl std::fmt::Debug for TitleText {
 }
 
 impl TitleText {
-    pub fn new(color: Color) -> Result<Self, crossfont::Error> {
+    pub fn new(color: Color) -> Result<Self, TitleTextError> {
         let title = "".into();
         let scale = 1;
 
@@ -66,15 +172,34 @@ impl TitleText {
             metrics,
             rasterizer,
             color,
+            glyph_cache: HashMap::new(),
+            fallback_fonts: FALLBACK_FAMILIES
+                .iter()
+                .map(|family| FallbackFont {
+                    desc: crossfont::FontDesc::new(
+                        *family,
+                        crossfont::Style::Description {
+                            slant: crossfont::Slant::Normal,
+                            weight: crossfont::Weight::Normal,
+                        },
+                    ),
+                    key: None,
+                })
+                .collect(),
+            char_font_cache: HashMap::new(),
+            gamma: DEFAULT_GAMMA,
+            contrast: DEFAULT_CONTRAST,
+            gamma_lut: [0; 256],
             pixmap: None,
         };
 
-        this.rerender();
+        this.rebuild_gamma_lut();
+        this.rerender()?;
 
         Ok(this)
     }
 
-    fn update_metrics(&mut self) -> Result<(), crossfont::Error> {
+    fn update_metrics(&mut self) -> Result<(), TitleTextError> {
         self.rasterizer.get_glyph(GlyphKey {
             font_key: self.font_key,
             character: 'm',
@@ -84,96 +209,276 @@ impl TitleText {
         Ok(())
     }
 
-    pub fn update_scale(&mut self, scale: u32) {
+    pub fn update_scale(&mut self, scale: u32) -> Result<(), TitleTextError> {
         if self.scale != scale {
+            let previous_scale = self.scale;
+            let previous_metrics = self.metrics;
+
             self.rasterizer.update_dpr(scale as f32);
             self.scale = scale;
 
-            self.update_metrics().ok();
-
-            self.rerender();
+            // Cached glyphs were rasterized for the old DPR, so they no
+            // longer match what the rasterizer would produce now.
+            self.glyph_cache.clear();
+            self.rebuild_gamma_lut();
+
+            if let Err(err) = self.update_metrics().and_then(|()| self.rerender()) {
+                // Roll back to the last known-good scale so a retry with
+                // the same value isn't a silent no-op.
+                self.rasterizer.update_dpr(previous_scale as f32);
+                self.scale = previous_scale;
+                self.metrics = previous_metrics;
+                self.glyph_cache.clear();
+                return Err(err);
+            }
         }
+
+        Ok(())
     }
 
-    pub fn update_title<S: Into<String>>(&mut self, title: S) {
+    pub fn update_title<S: Into<String>>(&mut self, title: S) -> Result<(), TitleTextError> {
         let title = title.into();
         if self.title != title {
-            self.title = title;
-            self.rerender();
+            let previous_title = std::mem::replace(&mut self.title, title);
+
+            if let Err(err) = self.rerender() {
+                self.title = previous_title;
+                return Err(err);
+            }
         }
+
+        Ok(())
     }
 
-    pub fn update_color(&mut self, color: Color) {
+    pub fn update_color(&mut self, color: Color) -> Result<(), TitleTextError> {
         if self.color != color {
+            let previous_color = self.color;
+
             self.color = color;
-            self.rerender();
+            self.rebuild_gamma_lut();
+
+            if let Err(err) = self.rerender() {
+                self.color = previous_color;
+                self.rebuild_gamma_lut();
+                return Err(err);
+            }
         }
+
+        Ok(())
     }
 
-    fn rerender(&mut self) {
-        let glyphs: Vec<_> = self
-            .title
-            .chars()
-            .filter_map(|character| {
-                let key = GlyphKey {
-                    character,
-                    font_key: self.font_key,
-                    size: self.size,
-                };
+    /// Set the gamma used by the coverage-to-alpha lookup table. See
+    /// [`DEFAULT_GAMMA`] for the default, sRGB-targeted value.
+    pub fn update_gamma(&mut self, gamma: f32) -> Result<(), TitleTextError> {
+        if self.gamma != gamma {
+            let previous_gamma = self.gamma;
+
+            self.gamma = gamma;
+            self.rebuild_gamma_lut();
+
+            if let Err(err) = self.rerender() {
+                self.gamma = previous_gamma;
+                self.rebuild_gamma_lut();
+                return Err(err);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Set the contrast boost applied on top of the gamma curve. See
+    /// [`DEFAULT_CONTRAST`] for the default value.
+    pub fn update_contrast(&mut self, contrast: f32) -> Result<(), TitleTextError> {
+        if self.contrast != contrast {
+            let previous_contrast = self.contrast;
+
+            self.contrast = contrast;
+            self.rebuild_gamma_lut();
 
-                self.rasterizer
-                    .get_glyph(key)
-                    .map(|glyph| (key, glyph))
-                    .ok()
-            })
-            .collect();
+            if let Err(err) = self.rerender() {
+                self.contrast = previous_contrast;
+                self.rebuild_gamma_lut();
+                return Err(err);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Rebuild the coverage-to-alpha lookup table from `self.gamma` and
+    /// `self.contrast`, biased by the foreground color's luminance against
+    /// an approximate titlebar background luminance. This keeps light
+    /// text on dark titlebars from looking too thin, and dark text on
+    /// light titlebars from looking too heavy.
+    fn rebuild_gamma_lut(&mut self) {
+        let light_on_dark = luminance(self.color) > APPROX_BACKGROUND_LUMINANCE;
+        let gamma = if light_on_dark { 1.0 / self.gamma } else { self.gamma };
+
+        for (i, entry) in self.gamma_lut.iter_mut().enumerate() {
+            let coverage = i as f32 / 255.0;
+            let corrected = coverage.powf(gamma);
+            let corrected = corrected + self.contrast * (corrected - corrected * corrected);
+            *entry = (corrected.clamp(0.0, 1.0) * 255.0).round() as u8;
+        }
+    }
+
+    /// Try rasterizing `character` with `font_key`, populating
+    /// `glyph_cache` on success. Returns `font_key` back so it composes
+    /// with the resolution loop in [`Self::resolve_font`]; returns `None`
+    /// if this face can't render the character.
+    ///
+    /// This folds the "can this face render it" probe and the
+    /// cache-populating rasterization into the same `get_glyph` call, so
+    /// a glyph seen for the first time is only rasterized once.
+    fn try_face(
+        &mut self,
+        font_key: crossfont::FontKey,
+        character: char,
+    ) -> Option<crossfont::FontKey> {
+        let key = GlyphKey {
+            font_key,
+            character,
+            size: self.size,
+        };
+
+        if let Entry::Vacant(entry) = self.glyph_cache.entry(key) {
+            let glyph = self.rasterizer.get_glyph(key).ok()?;
+            let colored = matches!(
+                &glyph.buffer,
+                crossfont::BitmapBuffer::Rgba(v) if is_color_bitmap(v)
+            );
+            entry.insert(CachedGlyph { glyph, colored });
+        }
+
+        Some(font_key)
+    }
+
+    /// Find the face that can render `character`, trying the primary face
+    /// first and then walking the fallback list. The winning face is
+    /// cached so repeated characters don't re-probe every face.
+    fn resolve_font(&mut self, character: char) -> Option<crossfont::FontKey> {
+        if let Some(&font_key) = self.char_font_cache.get(&character) {
+            return self.try_face(font_key, character);
+        }
+
+        if let Some(font_key) = self.try_face(self.font_key, character) {
+            self.char_font_cache.insert(character, font_key);
+            return Some(font_key);
+        }
+
+        for i in 0..self.fallback_fonts.len() {
+            let font_key = match self.fallback_fonts[i].key {
+                Some(Some(font_key)) => font_key,
+                Some(None) => continue,
+                None => match self.rasterizer.load_font(&self.fallback_fonts[i].desc, self.size) {
+                    Ok(font_key) => {
+                        self.fallback_fonts[i].key = Some(Some(font_key));
+                        font_key
+                    }
+                    Err(_) => {
+                        self.fallback_fonts[i].key = Some(None);
+                        continue;
+                    }
+                },
+            };
+
+            if let Some(font_key) = self.try_face(font_key, character) {
+                self.char_font_cache.insert(character, font_key);
+                return Some(font_key);
+            }
+        }
+
+        None
+    }
+
+    fn rerender(&mut self) -> Result<(), TitleTextError> {
+        let mut keys = Vec::with_capacity(self.title.len());
+        // Track the first character no face could render, so an entirely
+        // blank result can still be distinguished from an actually empty
+        // title; a single stray codepoint (a bad variation selector, a
+        // symbol missing from every fallback) should just be skipped, not
+        // blank the whole title bar.
+        let mut first_missing = None;
+
+        for character in self.title.chars() {
+            let font_key = match self.resolve_font(character) {
+                Some(font_key) => font_key,
+                None => {
+                    first_missing.get_or_insert(character);
+                    continue;
+                }
+            };
+            let key = GlyphKey {
+                character,
+                font_key,
+                size: self.size,
+            };
+
+            // `resolve_font` already rasterized and cached this glyph
+            // while probing which face could render it.
+            keys.push(key);
+        }
+
+        if keys.is_empty() {
+            // On failure, leave the previously-rendered pixmap in place
+            // rather than blanking the titlebar — the caller's rolled-back
+            // title/color/scale should keep matching what's on screen.
+            if let Some(character) = first_missing {
+                return Err(TitleTextError::MissingGlyph(character));
+            }
 
-        if glyphs.is_empty() {
             self.pixmap = None;
-            return;
+            return Ok(());
         }
 
-        let width = glyphs
-            .iter()
-            .fold(0, |w, (_, g)| w + (g.left + g.width).max(5));
+        let width = keys.iter().fold(0, |w, key| {
+            let glyph = &self.glyph_cache[key].glyph;
+            w + (glyph.left + glyph.width).max(5)
+        });
         let height = self.metrics.line_height.round() as i32;
 
-        let mut pixmap = if let Some(p) = Pixmap::new(width as u32, height as u32) {
-            p
-        } else {
-            self.pixmap = None;
-            return;
-        };
+        let mut pixmap = Pixmap::new(width as u32, height as u32)
+            .ok_or(TitleTextError::PixmapAllocation)?;
         // pixmap.fill(Color::from_rgba8(255, 0, 0, 55));
 
         let mut caret = 0;
         let mut last_glyph = None;
 
-        for (key, glyph) in glyphs {
+        for key in keys {
+            let cached = &self.glyph_cache[&key];
+            let glyph = &cached.glyph;
             let mut buffer = Vec::with_capacity(glyph.width as usize * 4);
 
+            // Color bitmap glyphs (e.g. emoji) already carry their own RGBA
+            // pixels and must be copied through as-is; only grayscale
+            // coverage masks get tinted with `self.color`.
+            let is_color_glyph = cached.colored;
+
             let glyph_buffer = match &glyph.buffer {
                 crossfont::BitmapBuffer::Rgb(v) => v.chunks(3),
                 crossfont::BitmapBuffer::Rgba(v) => v.chunks(4),
             };
 
             for px in glyph_buffer {
-                let alpha = if let Some(alpha) = px.get(3) {
-                    *alpha as f32 / 255.0
+                if is_color_glyph {
+                    buffer.extend_from_slice(&color_glyph_pixel(px));
+                    continue;
+                }
+
+                let coverage = if let Some(alpha) = px.get(3) {
+                    *alpha
                 } else {
-                    let r = px[0] as f32 / 255.0;
-                    let g = px[1] as f32 / 255.0;
-                    let b = px[2] as f32 / 255.0;
-                    (r + g + b) / 3.0
+                    ((px[0] as u32 + px[1] as u32 + px[2] as u32) / 3) as u8
                 };
+                let alpha = self.gamma_lut[coverage as usize] as f32 / 255.0;
 
                 let mut color = self.color;
                 color.set_alpha(alpha);
                 let color = color.premultiply().to_color_u8();
 
-                buffer.push(color.red());
                 buffer.push(color.red());
                 buffer.push(color.green());
+                buffer.push(color.blue());
                 buffer.push(color.alpha());
             }
 
@@ -201,9 +506,41 @@ impl TitleText {
         }
 
         self.pixmap = Some(pixmap);
+
+        Ok(())
     }
 
     pub fn pixmap(&self) -> Option<&Pixmap> {
         self.pixmap.as_ref()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_color_bitmap_ignores_grayscale_coverage() {
+        // R == G == B on every pixel, as a coverage mask would produce.
+        let coverage = [100, 100, 100, 255, 50, 50, 50, 128];
+        assert!(!is_color_bitmap(&coverage));
+    }
+
+    #[test]
+    fn is_color_bitmap_detects_real_color() {
+        let emoji = [255, 0, 0, 255];
+        assert!(is_color_bitmap(&emoji));
+    }
+
+    #[test]
+    fn color_glyph_pixel_preserves_rgba_order() {
+        let px = [10, 20, 30, 255];
+        assert_eq!(color_glyph_pixel(&px), [10, 20, 30, 255]);
+    }
+
+    #[test]
+    fn luminance_of_white_and_black() {
+        assert!((luminance(Color::WHITE) - 1.0).abs() < 1e-6);
+        assert!(luminance(Color::BLACK).abs() < 1e-6);
+    }
+}